@@ -1,59 +1,120 @@
+mod plugins;
+
 use glam::Vec2;
-use serde::{Deserialize, Serialize};
+use plugins::{PluginAction, PluginHost};
+use shared::crypto::{self, Session};
+use shared::{simulate, ClientMessage, Input, RemoteState, ServerMessage};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::RwLock;
 use warp::ws::Message;
 use warp::ws::WebSocket;
 use warp::Filter;
+use ed25519_dalek::SigningKey;
+use x25519_dalek::{PublicKey, StaticSecret};
 
-#[derive(Deserialize, Serialize, Clone)]
-pub struct State {
-    pub pos: Vec2,
-    pub r: f32,
-}
-
-#[derive(Deserialize, Serialize, Clone)]
-pub struct RemoteState {
-    pub id: usize,
-    pub position: Vec2,
-    pub rotation: f32,
-}
+async fn send_msg(tx: &OutBoundChannel, session: Option<&Session>, msg: &ServerMessage) {
+    let buffer = bincode::serialize(msg).unwrap();
 
-#[derive(Deserialize, Serialize)]
-pub enum ServerMessage {
-    Welcome(usize),
-    GoodBye(usize),
-    Update(Vec<RemoteState>),
-}
-
-#[derive(Deserialize, Serialize)]
-pub enum ClientMessage {
-    State(State),
-}
-
-async fn send_msg(tx: &OutBoundChannel, msg: &ServerMessage) {
-    let buffer = serde_json::to_vec(msg).unwrap();
+    // Encrypt once the session is established; the handshake frames themselves
+    // (Welcome / Disconnect) are sent in the clear.
+    let buffer = match session {
+        Some(session) => session.encrypt(&buffer),
+        None => buffer,
+    };
 
     let msg = Message::binary(buffer);
 
-    tx.send(Ok(msg)).unwrap();
+    // The receiving forward task may already be gone if the socket closed; drop
+    // the message rather than panicking the whole broadcast loop.
+    if let Err(e) = tx.send(Ok(msg)) {
+        log::debug!("dropping message to closed channel: {}", e);
+    }
 }
 
-async fn user_connected(ws: WebSocket, users: Users, states: States) {
+async fn user_connected(
+    ws: WebSocket,
+    users: Users,
+    states: States,
+    inputs: Inputs,
+    plugins: PluginHost,
+    keys: ServerKeys,
+    identities: Identities,
+) {
     use futures_util::StreamExt;
 
     let (ws_sender, mut ws_receiver) = ws.split();
 
     let send_channel = create_send_channel(ws_sender);
 
-    let my_id = send_welcome(&send_channel).await;
-
-    log::debug!("new user connected: {}", my_id);
-
-    users.write().await.insert(my_id, send_channel);
+    // Authenticate before anything else: a connection that can't prove an
+    // identity never becomes a player.
+    let (identity, session, server_signature) =
+        match handshake(ws_receiver.next().await, &send_channel, &keys).await {
+            Some(triple) => triple,
+            None => return,
+        };
+    let session = Arc::new(session);
+    let label = crypto::identity_hex(&identity);
+
+    // Reuse the id we already assigned this identity so a reconnecting player
+    // keeps the same plane and skin.
+    let my_id = {
+        let mut identities = identities.write().await;
+        match identities.get(&label) {
+            Some(&id) => id,
+            None => {
+                let id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+                identities.insert(label.clone(), id);
+                id
+            }
+        }
+    };
+
+    let conn_token = NEXT_CONN_TOKEN.fetch_add(1, Ordering::Relaxed);
+
+    log::debug!("new user connected: {} ({})", my_id, label);
+
+    // Welcome is plaintext so the client can derive the matching session key;
+    // everything after it is encrypted.
+    send_msg(
+        &send_channel,
+        None,
+        &ServerMessage::Welcome {
+            id: my_id,
+            server_key: keys.public.to_vec(),
+            server_identity: keys.identity.to_vec(),
+            signature: server_signature,
+        },
+    )
+    .await;
+
+    users.write().await.insert(
+        my_id,
+        User {
+            tx: send_channel,
+            last_pong: now_ms(),
+            session: session.clone(),
+            token: conn_token,
+        },
+    );
+    states.write().await.insert(
+        my_id,
+        RemoteState {
+            id: my_id,
+            position: Vec2::new(100f32, 100f32),
+            rotation: 0f32,
+            last_processed_input: 0,
+            identity: Some(label),
+        },
+    );
+    inputs.write().await.insert(my_id, Input::default());
+
+    let actions = plugins.on_player_join(my_id).await;
+    apply_actions(actions, &users, &states, &inputs).await;
 
     while let Some(result) = ws_receiver.next().await {
         let msg = match result {
@@ -66,45 +127,162 @@ async fn user_connected(ws: WebSocket, users: Users, states: States) {
 
         log::debug!("user sent message: {:?}", msg);
 
-        if let Some(msg) = parse_message(msg) {
-            user_message(my_id, msg, &states).await;
+        if let Some(msg) = parse_message(msg, Some(&session)) {
+            let actions = plugins.on_message(my_id, msg.clone()).await;
+            apply_actions(actions, &users, &states, &inputs).await;
+
+            user_message(my_id, msg, &inputs, &users).await;
         }
     }
 
     log::debug!("user disconnected: {}", my_id);
 
-    users.write().await.remove(&my_id);
+    // A player who reconnects keeps its id, so by the time this task tears down
+    // the id may already belong to a newer, live connection. Only remove the
+    // entry if it is still the one we inserted; otherwise leave the replacement
+    // untouched and bow out quietly.
+    {
+        let mut users = users.write().await;
+        match users.get(&my_id) {
+            Some(user) if user.token == conn_token => {
+                users.remove(&my_id);
+            }
+            _ => {
+                log::debug!("id {} already reclaimed by a newer connection", my_id);
+                return;
+            }
+        }
+    }
+
     states.write().await.remove(&my_id);
+    inputs.write().await.remove(&my_id);
+
+    let actions = plugins.on_player_leave(my_id).await;
+    apply_actions(actions, &users, &states, &inputs).await;
 
     broadcast(ServerMessage::GoodBye(my_id), &users).await;
 }
 
-fn parse_message(msg: Message) -> Option<ClientMessage> {
+// Verify the client's opening `Hello` and, on success, derive the encrypted
+// session from an x25519 exchange. If the first frame is not a valid `Hello`, a
+// typed `Disconnect` is sent before the connection is dropped; a connection
+// that closes or errors before sending anything is dropped without one.
+async fn handshake(
+    first: Option<Result<Message, warp::Error>>,
+    tx: &OutBoundChannel,
+    keys: &ServerKeys,
+) -> Option<(Vec<u8>, Session, Vec<u8>)> {
+    let msg = match first {
+        Some(Ok(msg)) => msg,
+        _ => return None,
+    };
+
+    let (identity, eph_pubkey, signature) = match parse_message(msg, None) {
+        Some(ClientMessage::Hello {
+            identity,
+            eph_pubkey,
+            signature,
+        }) => (identity, eph_pubkey, signature),
+        _ => {
+            reject(tx, "expected handshake").await;
+            return None;
+        }
+    };
+
+    if !crypto::verify_hello(&identity, &eph_pubkey, &signature) {
+        reject(tx, "signature verification failed").await;
+        return None;
+    }
+
+    let client_key: [u8; 32] = match eph_pubkey.as_slice().try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            reject(tx, "malformed handshake key").await;
+            return None;
+        }
+    };
+
+    let shared = keys
+        .secret
+        .diffie_hellman(&PublicKey::from(client_key))
+        .to_bytes();
+
+    // Authenticate ourselves back to the client, binding the client's
+    // ephemeral key so the proof can't be lifted into another session.
+    let signature = crypto::sign_server_bind(&keys.signing, &eph_pubkey, &keys.public);
+
+    Some((
+        identity,
+        Session::new(shared, crypto::DIRECTION_SERVER),
+        signature,
+    ))
+}
+
+async fn reject(tx: &OutBoundChannel, reason: &str) {
+    log::warn!("rejecting connection: {}", reason);
+    send_msg(tx, None, &ServerMessage::Disconnect(reason.to_string())).await;
+}
+
+// Apply the changes a plugin requested during a callback.
+async fn apply_actions(actions: Vec<PluginAction>, users: &Users, states: &States, inputs: &Inputs) {
+    for action in actions {
+        match action {
+            PluginAction::Kick(id) => {
+                users.write().await.remove(&id);
+                states.write().await.remove(&id);
+                inputs.write().await.remove(&id);
+                broadcast(ServerMessage::GoodBye(id), users).await;
+            }
+            PluginAction::SetState { id, x, y, rotation } => {
+                if let Some(state) = states.write().await.get_mut(&id) {
+                    state.position = Vec2::new(x, y);
+                    state.rotation = rotation;
+                }
+            }
+            PluginAction::Send(id, msg) => send_to(id, msg, users).await,
+            PluginAction::Broadcast(msg) => broadcast(msg, users).await,
+        }
+    }
+}
+
+async fn send_to(id: usize, msg: ServerMessage, users: &Users) {
+    if let Some(user) = users.read().await.get(&id) {
+        send_msg(&user.tx, Some(&user.session), &msg).await;
+    }
+}
+
+fn parse_message(msg: Message, session: Option<&Session>) -> Option<ClientMessage> {
     if msg.is_binary() {
         let msg = msg.into_bytes();
-        serde_json::from_slice::<ClientMessage>(msg.as_slice()).ok()
+        let plaintext = match session {
+            Some(session) => session.decrypt(msg.as_slice())?,
+            None => msg.to_vec(),
+        };
+        bincode::deserialize::<ClientMessage>(plaintext.as_slice()).ok()
     } else {
         None
     }
 }
 
-async fn user_message(my_id: usize, msg: ClientMessage, states: &States) {
+async fn user_message(my_id: usize, msg: ClientMessage, inputs: &Inputs, users: &Users) {
     match msg {
-        ClientMessage::State(state) => {
-            let msg = RemoteState {
-                id: my_id,
-                position: state.pos,
-                rotation: state.r,
-            };
-            states.write().await.insert(msg.id, msg);
+        ClientMessage::Input(input) => {
+            inputs.write().await.insert(my_id, input);
         }
+        ClientMessage::Pong(_nonce) => {
+            if let Some(user) = users.write().await.get_mut(&my_id) {
+                user.last_pong = now_ms();
+            }
+        }
+        // The handshake is already done; a repeated Hello is ignored.
+        ClientMessage::Hello { .. } => {}
     }
 }
 
 async fn broadcast(msg: ServerMessage, users: &Users) {
     let users = users.read().await;
-    for (_, tx) in users.iter() {
-        send_msg(tx, &msg).await;
+    for user in users.values() {
+        send_msg(&user.tx, Some(&user.session), &msg).await;
     }
 }
 
@@ -131,45 +309,232 @@ fn create_send_channel(
 
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-async fn send_welcome(out: &OutBoundChannel) -> usize {
-    let id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+// A monotonic tag for each physical connection. Because a reconnecting player
+// reuses its `id`, two connections can briefly share one id; the token lets the
+// cleanup path tell whether the entry it is about to remove is still its own.
+static NEXT_CONN_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// How many simulation ticks to run between broadcasts. The simulation
+// advances at ~60Hz while the authoritative state is broadcast every 100ms.
+const BROADCAST_EVERY: u32 = 6;
 
-    let states = ServerMessage::Welcome(id);
+// Drop back to a full `Update` rather than a `Delta` once a client's last
+// snapshot is more than this many ticks behind.
+const MAX_DELTA_AGE: u32 = 60;
 
-    send_msg(out, &states).await;
+// The last full snapshot sent to one client, used as the base for its deltas.
+struct SentSnapshot {
+    tick: u32,
+    states: HashMap<usize, RemoteState>,
+}
 
-    id
+// Quantize a plane so sub-pixel / sub-degree jitter doesn't count as a change.
+fn quantize(state: &RemoteState) -> (i32, i32, i32) {
+    (
+        state.position.x.round() as i32,
+        state.position.y.round() as i32,
+        (state.rotation * 100.).round() as i32,
+    )
 }
 
-async fn update_loop(users: Users, states: States) {
+async fn update_loop(users: Users, states: States, inputs: Inputs, plugins: PluginHost) {
+    let mut tick: u32 = 0;
+    let mut last_sent: HashMap<usize, SentSnapshot> = HashMap::new();
+
     loop {
-        let states: Vec<RemoteState> = states.read().await.values().cloned().collect();
-
-        if !states.is_empty() {
-            for (&uid, tx) in users.read().await.iter() {
-                let states = states
-                    .iter()
-                    .filter_map(|state| {
-                        if state.id == uid {
-                            None
-                        } else {
-                            Some(state.clone())
-                        }
-                    })
-                    .collect();
+        {
+            let inputs = inputs.read().await;
+            let mut states = states.write().await;
 
-                let states = ServerMessage::Update(states);
+            for (id, state) in states.iter_mut() {
+                let input = inputs.get(id).copied().unwrap_or_default();
+                simulate(state, &input, 1.0);
+            }
+        }
 
-                send_msg(tx, &states).await;
+        let snapshot: Vec<RemoteState> = states.read().await.values().cloned().collect();
+        let actions = plugins.on_tick(snapshot).await;
+        apply_actions(actions, &users, &states, &inputs).await;
+
+        if tick % BROADCAST_EVERY == 0 {
+            let world: HashMap<usize, RemoteState> = states.read().await.clone();
+
+            if !world.is_empty() {
+                let users = users.read().await;
+
+                for (&uid, user) in users.iter() {
+                    // Send a delta when we have a recent base for this client,
+                    // otherwise a full update (new client or base too old).
+                    let msg = match last_sent.get(&uid) {
+                        Some(prev) if tick.wrapping_sub(prev.tick) <= MAX_DELTA_AGE => {
+                            let changed = world
+                                .values()
+                                .filter(|state| match prev.states.get(&state.id) {
+                                    // Include a plane when its position/rotation
+                                    // moved or its input ack advanced, so the
+                                    // client's reconcile always sees progress
+                                    // even for a stationary plane.
+                                    Some(old) => {
+                                        quantize(old) != quantize(state)
+                                            || old.last_processed_input
+                                                != state.last_processed_input
+                                    }
+                                    None => true,
+                                })
+                                .cloned()
+                                .collect();
+                            let removed = prev
+                                .states
+                                .keys()
+                                .filter(|id| !world.contains_key(id))
+                                .copied()
+                                .collect();
+
+                            ServerMessage::Delta {
+                                base_tick: prev.tick,
+                                tick,
+                                changed,
+                                removed,
+                            }
+                        }
+                        _ => ServerMessage::Update {
+                            states: world.values().cloned().collect(),
+                            tick,
+                        },
+                    };
+
+                    send_msg(&user.tx, Some(&user.session), &msg).await;
+
+                    last_sent.insert(
+                        uid,
+                        SentSnapshot {
+                            tick,
+                            states: world.clone(),
+                        },
+                    );
+                }
+
+                // Forget clients that have disconnected.
+                last_sent.retain(|id, _| users.contains_key(id));
             }
         }
 
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        tick = tick.wrapping_add(1);
+
+        tokio::time::sleep(std::time::Duration::from_millis(16)).await;
     }
 }
 
-type Users = Arc<RwLock<HashMap<usize, OutBoundChannel>>>;
+struct User {
+    tx: OutBoundChannel,
+    last_pong: u64,
+    session: Arc<Session>,
+    // Identifies the connection that owns this entry, so a stale task whose
+    // player has already reconnected under the same id doesn't clobber it.
+    token: u64,
+}
+
+// The server's long-lived keys: an x25519 keypair for the session exchange and
+// an ed25519 keypair the server uses to authenticate itself to clients.
+#[derive(Clone)]
+struct ServerKeys {
+    secret: Arc<StaticSecret>,
+    public: [u8; 32],
+    signing: Arc<SigningKey>,
+    identity: [u8; 32],
+}
+
+type Users = Arc<RwLock<HashMap<usize, User>>>;
 type States = Arc<RwLock<HashMap<usize, RemoteState>>>;
+type Inputs = Arc<RwLock<HashMap<usize, Input>>>;
+// Maps a stable player identity to the id (and therefore plane/skin) it keeps
+// across reconnects.
+type Identities = Arc<RwLock<HashMap<String, usize>>>;
+
+// How often to ping clients and how long to wait for a pong before evicting.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const PONG_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Send a keep-alive ping (carrying the current timestamp as its nonce) to every
+// connected client on a fixed interval.
+async fn ping_loop(users: Users) {
+    loop {
+        tokio::time::sleep(PING_INTERVAL).await;
+
+        broadcast(ServerMessage::Ping(now_ms()), &users).await;
+    }
+}
+
+// Evict clients that have not answered a ping within `PONG_TIMEOUT`, cleaning up
+// their state and telling everyone else they left.
+async fn reaper_loop(users: Users, states: States, inputs: Inputs) {
+    loop {
+        tokio::time::sleep(PING_INTERVAL).await;
+
+        let deadline = now_ms().saturating_sub(PONG_TIMEOUT.as_millis() as u64);
+
+        let stale: Vec<usize> = users
+            .read()
+            .await
+            .iter()
+            .filter_map(|(&id, user)| (user.last_pong < deadline).then_some(id))
+            .collect();
+
+        for id in stale {
+            log::warn!("evicting unresponsive user: {}", id);
+
+            users.write().await.remove(&id);
+            states.write().await.remove(&id);
+            inputs.write().await.remove(&id);
+
+            broadcast(ServerMessage::GoodBye(id), &users).await;
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Heartbeat<'a> {
+    address: &'a str,
+    name: &'a str,
+    players: usize,
+}
+
+// Announce this server to a master server list so it shows up in a browser.
+// Configured through the `MASTER_LIST_URL`, `SERVER_ADDRESS` and `SERVER_NAME`
+// environment variables; does nothing if no master list is configured.
+async fn heartbeat_loop(users: Users) {
+    let master_url = match std::env::var("MASTER_LIST_URL") {
+        Ok(url) => url,
+        Err(_) => return,
+    };
+    let address = std::env::var("SERVER_ADDRESS").unwrap_or_else(|_| "localhost:3030".into());
+    let name = std::env::var("SERVER_NAME").unwrap_or_else(|_| "warp".into());
+
+    let client = reqwest::Client::new();
+
+    loop {
+        let players = users.read().await.len();
+
+        let body = Heartbeat {
+            address: &address,
+            name: &name,
+            players,
+        };
+
+        if let Err(e) = client.post(&master_url).json(&body).send().await {
+            log::warn!("heartbeat failed: {}", e);
+        }
+
+        tokio::time::sleep(Duration::from_secs(45)).await;
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -177,22 +542,60 @@ async fn main() {
 
     let users = Users::default();
     let states = States::default();
+    let inputs = Inputs::default();
+    let identities = Identities::default();
+    let plugins = PluginHost::spawn("plugins");
+
+    let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let signing = SigningKey::generate(&mut rand::rngs::OsRng);
+    let keys = ServerKeys {
+        public: PublicKey::from(&secret).to_bytes(),
+        secret: Arc::new(secret),
+        identity: signing.verifying_key().to_bytes(),
+        signing: Arc::new(signing),
+    };
+    log::info!("server identity: {}", crypto::identity_hex(&keys.identity));
 
     let arc_users = users.clone();
     let arc_states = states.clone();
+    let arc_inputs = inputs.clone();
+    let arc_plugins = plugins.clone();
+
+    tokio::spawn(async move { update_loop(arc_users, arc_states, arc_inputs, arc_plugins).await });
+
+    let ping_users = users.clone();
+    tokio::spawn(async move { ping_loop(ping_users).await });
+
+    let reaper_users = users.clone();
+    let reaper_states = states.clone();
+    let reaper_inputs = inputs.clone();
+    tokio::spawn(async move { reaper_loop(reaper_users, reaper_states, reaper_inputs).await });
 
-    tokio::spawn(async move { update_loop(arc_users, arc_states).await });
+    let heartbeat_users = users.clone();
+    tokio::spawn(async move { heartbeat_loop(heartbeat_users).await });
 
     let users = warp::any().map(move || users.clone());
     let states = warp::any().map(move || states.clone());
+    let inputs = warp::any().map(move || inputs.clone());
+    let plugins = warp::any().map(move || plugins.clone());
+    let keys = warp::any().map(move || keys.clone());
+    let identities = warp::any().map(move || identities.clone());
 
     let game = warp::path("game")
         .and(warp::ws())
         .and(users)
         .and(states)
-        .map(|ws: warp::ws::Ws, users, states| {
-            ws.on_upgrade(move |socket| user_connected(socket, users, states))
-        });
+        .and(inputs)
+        .and(plugins)
+        .and(keys)
+        .and(identities)
+        .map(
+            |ws: warp::ws::Ws, users, states, inputs, plugins, keys, identities| {
+                ws.on_upgrade(move |socket| {
+                    user_connected(socket, users, states, inputs, plugins, keys, identities)
+                })
+            },
+        );
 
     let status = warp::path!("status").map(|| warp::reply::html("hello"));
 
@@ -0,0 +1,328 @@
+use mlua::{Function, Lua, Table, Value};
+use shared::crypto;
+use shared::{ClientMessage, RemoteState, ServerMessage};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+// A change a plugin asks the server to make, queued during a callback and
+// applied afterwards so the Lua side stays synchronous.
+pub enum PluginAction {
+    Kick(usize),
+    SetState {
+        id: usize,
+        x: f32,
+        y: f32,
+        rotation: f32,
+    },
+    Send(usize, ServerMessage),
+    Broadcast(ServerMessage),
+}
+
+// An event dispatched to the plugin thread, paired with a channel to send the
+// resulting actions back on.
+enum PluginEvent {
+    Join(usize),
+    Leave(usize),
+    Message(usize, ClientMessage),
+    Tick(Vec<RemoteState>),
+}
+
+struct PluginRequest {
+    event: PluginEvent,
+    reply: oneshot::Sender<Vec<PluginAction>>,
+}
+
+// A `Send` handle to the plugins. `mlua::Lua` is `!Send`, so the Lua state lives
+// on a dedicated OS thread and all interaction goes through this channel; the
+// handle itself is cheap to clone and share across tasks.
+#[derive(Clone)]
+pub struct PluginHost {
+    tx: mpsc::UnboundedSender<PluginRequest>,
+}
+
+impl PluginHost {
+    pub fn spawn(dir: impl AsRef<Path> + Send + 'static) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<PluginRequest>();
+
+        std::thread::spawn(move || {
+            let plugins = Plugins::load(dir);
+
+            while let Some(request) = rx.blocking_recv() {
+                let actions = plugins.handle(request.event);
+                let _ = request.reply.send(actions);
+            }
+        });
+
+        Self { tx }
+    }
+
+    async fn request(&self, event: PluginEvent) -> Vec<PluginAction> {
+        let (reply, rx) = oneshot::channel();
+        if self.tx.send(PluginRequest { event, reply }).is_err() {
+            return Vec::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    pub async fn on_player_join(&self, id: usize) -> Vec<PluginAction> {
+        self.request(PluginEvent::Join(id)).await
+    }
+
+    pub async fn on_player_leave(&self, id: usize) -> Vec<PluginAction> {
+        self.request(PluginEvent::Leave(id)).await
+    }
+
+    pub async fn on_message(&self, id: usize, msg: ClientMessage) -> Vec<PluginAction> {
+        self.request(PluginEvent::Message(id, msg)).await
+    }
+
+    pub async fn on_tick(&self, states: Vec<RemoteState>) -> Vec<PluginAction> {
+        self.request(PluginEvent::Tick(states)).await
+    }
+}
+
+// Loads the Lua scripts found in a directory and dispatches the game's
+// lifecycle events to whichever callbacks each script exposes. Lives entirely
+// on the plugin thread owned by `PluginHost`.
+struct Plugins {
+    lua: Lua,
+    plugins: Vec<Table>,
+    actions: Arc<Mutex<Vec<PluginAction>>>,
+    // The latest world snapshot, kept so `host.get_state` can read a player's
+    // `RemoteState` from any callback.
+    world: Arc<Mutex<Vec<RemoteState>>>,
+}
+
+impl Plugins {
+    // Load every `*.lua` file in `dir`. Each script is expected to return a
+    // table of callbacks, e.g. `return { on_player_join = function(id) .. end }`.
+    fn load(dir: impl AsRef<Path>) -> Self {
+        let lua = Lua::new();
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let world = Arc::new(Mutex::new(Vec::new()));
+
+        register_host(&lua, &actions, &world);
+
+        let mut plugins = Vec::new();
+
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("lua") {
+                    continue;
+                }
+
+                match std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|src| lua.load(&src).eval::<Table>().ok())
+                {
+                    Some(table) => {
+                        log::info!("loaded plugin: {}", path.display());
+                        plugins.push(table);
+                    }
+                    None => log::warn!("failed to load plugin: {}", path.display()),
+                }
+            }
+        }
+
+        Self {
+            lua,
+            plugins,
+            actions,
+            world,
+        }
+    }
+
+    fn handle(&self, event: PluginEvent) -> Vec<PluginAction> {
+        match event {
+            PluginEvent::Join(id) => self.dispatch("on_player_join", id),
+            PluginEvent::Leave(id) => self.dispatch("on_player_leave", id),
+            PluginEvent::Message(id, msg) => self.on_message(id, msg),
+            PluginEvent::Tick(states) => {
+                *self.world.lock().unwrap() = states.clone();
+                match self.states_table(&states) {
+                    Ok(table) => self.dispatch("on_tick", table),
+                    Err(e) => {
+                        log::warn!("plugin tick failed: {}", e);
+                        Vec::new()
+                    }
+                }
+            }
+        }
+    }
+
+    // Deliver the message payload to every `on_message` callback and route an
+    // optional returned message back to the sending player.
+    fn on_message(&self, id: usize, msg: ClientMessage) -> Vec<PluginAction> {
+        let table = match self.message_table(&msg) {
+            Ok(table) => table,
+            Err(e) => {
+                log::warn!("plugin on_message failed: {}", e);
+                return Vec::new();
+            }
+        };
+
+        for plugin in &self.plugins {
+            if let Ok(callback) = plugin.get::<Function>("on_message") {
+                match callback.call::<Value>((id, table.clone())) {
+                    Ok(Value::Table(reply)) => {
+                        if let Some(response) = message_from_table(&reply) {
+                            self.actions
+                                .lock()
+                                .unwrap()
+                                .push(PluginAction::Send(id, response));
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("plugin 'on_message' errored: {}", e),
+                }
+            }
+        }
+
+        std::mem::take(&mut self.actions.lock().unwrap())
+    }
+
+    fn message_table(&self, msg: &ClientMessage) -> mlua::Result<Table> {
+        let table = self.lua.create_table()?;
+        match msg {
+            ClientMessage::Hello { identity, .. } => {
+                table.set("kind", "hello")?;
+                table.set("identity", crypto::identity_hex(identity))?;
+            }
+            ClientMessage::Input(input) => {
+                table.set("kind", "input")?;
+                table.set("left", input.left)?;
+                table.set("right", input.right)?;
+                table.set("thrust", input.thrust)?;
+                table.set("seq", input.seq)?;
+            }
+            ClientMessage::Pong(nonce) => {
+                table.set("kind", "pong")?;
+                table.set("nonce", *nonce)?;
+            }
+        }
+        Ok(table)
+    }
+
+    fn states_table(&self, states: &[RemoteState]) -> mlua::Result<Table> {
+        let table = self.lua.create_table()?;
+        for state in states {
+            table.set(state.id, state_table(&self.lua, state)?)?;
+        }
+        Ok(table)
+    }
+
+    fn dispatch(&self, name: &str, args: impl mlua::IntoLuaMulti + Clone) -> Vec<PluginAction> {
+        for plugin in &self.plugins {
+            if let Ok(callback) = plugin.get::<Function>(name) {
+                if let Err(e) = callback.call::<()>(args.clone()) {
+                    log::warn!("plugin '{}' errored: {}", name, e);
+                }
+            }
+        }
+
+        std::mem::take(&mut self.actions.lock().unwrap())
+    }
+}
+
+fn state_table(lua: &Lua, state: &RemoteState) -> mlua::Result<Table> {
+    let entry = lua.create_table()?;
+    entry.set("id", state.id)?;
+    entry.set("x", state.position.x)?;
+    entry.set("y", state.position.y)?;
+    entry.set("rotation", state.rotation)?;
+    if let Some(identity) = &state.identity {
+        entry.set("identity", identity.clone())?;
+    }
+    Ok(entry)
+}
+
+// Build a `ServerMessage` from a Lua table of the form `{ kind = "...", .. }` as
+// passed to `host.send`/`host.broadcast` or returned from `on_message`.
+fn message_from_table(table: &Table) -> Option<ServerMessage> {
+    let kind: String = table.get("kind").ok()?;
+    match kind.as_str() {
+        "goodbye" => Some(ServerMessage::GoodBye(table.get("id").ok()?)),
+        "ping" => Some(ServerMessage::Ping(table.get("nonce").ok()?)),
+        "disconnect" => Some(ServerMessage::Disconnect(table.get("reason").ok()?)),
+        _ => None,
+    }
+}
+
+// Expose the host API as a global `host` table. Every function either records a
+// `PluginAction` to be applied once the callback returns, or reads from the
+// latest world snapshot.
+fn register_host(
+    lua: &Lua,
+    actions: &Arc<Mutex<Vec<PluginAction>>>,
+    world: &Arc<Mutex<Vec<RemoteState>>>,
+) {
+    let host = lua.create_table().unwrap();
+
+    let buffer = actions.clone();
+    host.set(
+        "kick",
+        lua.create_function(move |_, id: usize| {
+            buffer.lock().unwrap().push(PluginAction::Kick(id));
+            Ok(())
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let buffer = actions.clone();
+    host.set(
+        "set_state",
+        lua.create_function(move |_, (id, x, y, rotation): (usize, f32, f32, f32)| {
+            buffer
+                .lock()
+                .unwrap()
+                .push(PluginAction::SetState { id, x, y, rotation });
+            Ok(())
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let snapshot = world.clone();
+    host.set(
+        "get_state",
+        lua.create_function(move |lua, id: usize| {
+            match snapshot.lock().unwrap().iter().find(|s| s.id == id) {
+                Some(state) => Ok(Value::Table(state_table(lua, state)?)),
+                None => Ok(Value::Nil),
+            }
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let buffer = actions.clone();
+    host.set(
+        "send",
+        lua.create_function(move |_, (id, msg): (usize, Table)| {
+            if let Some(msg) = message_from_table(&msg) {
+                buffer.lock().unwrap().push(PluginAction::Send(id, msg));
+            }
+            Ok(())
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let buffer = actions.clone();
+    host.set(
+        "broadcast",
+        lua.create_function(move |_, msg: Table| {
+            if let Some(msg) = message_from_table(&msg) {
+                buffer.lock().unwrap().push(PluginAction::Broadcast(msg));
+            }
+            Ok(())
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    lua.globals().set("host", host).unwrap();
+}
@@ -1,17 +1,52 @@
+use ed25519_dalek::{Signer, SigningKey};
 use macroquad::prelude::*;
-use shared::{ClientMessage, RemoteState, ServerMessage, State};
+use shared::crypto::{self, Session, HANDSHAKE_CONTEXT};
+use shared::{
+    simulate, ClientMessage, Input, RemoteState, ServerMessage, PLANE_HEIGHT, PLANE_WIDTH,
+};
+use std::collections::VecDeque;
 use ws::Connection;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 mod ws;
 
-const PLANE_WIDTH: f32 = 32.;
-const PLANE_HEIGHT: f32 = 32.;
+// Render remote planes this far in the past so there are always two snapshots
+// to interpolate between at the 100ms broadcast cadence.
+const INTERPOLATION_DELAY: f64 = 0.1;
+const SNAPSHOT_BUFFER: usize = 8;
+
+// Above this positional error the local plane is snapped to the reconciled
+// position; below it the error is eased out over several frames so small
+// disagreements never cause a visible jump.
+const CORRECTION_THRESHOLD: f32 = 2.;
+const CORRECTION_SMOOTHING: f32 = 0.2;
+
+// A single authoritative `Update`, tagged with the local time it arrived so we
+// can place it on our own clock for interpolation.
+struct Snapshot {
+    received_at: f64,
+    tick: u32,
+    states: Vec<RemoteState>,
+}
+
+// A remote plane as it should be drawn this frame, with an alpha so planes that
+// just appeared fade in rather than pop.
+struct RenderPlane {
+    state: RemoteState,
+    alpha: f32,
+}
 
 pub struct Game {
     pub quit: bool,
     pub player_state: RemoteState,
     pub texture: Texture2D,
-    pub remote_states: Vec<RemoteState>,
+    snapshots: VecDeque<Snapshot>,
+    render_planes: Vec<RenderPlane>,
+    input_seq: u32,
+    last_input: Input,
+    // Inputs applied locally but not yet acknowledged by the server, kept so
+    // they can be replayed on top of an authoritative snapshot.
+    pending_inputs: VecDeque<Input>,
 }
 
 impl Game {
@@ -23,10 +58,16 @@ impl Game {
                 id: 0,
                 position: Vec2::new(100f32, 100f32),
                 rotation: 0f32,
+                last_processed_input: 0,
+                identity: None,
             },
             texture,
             quit: false,
-            remote_states: Vec::new(),
+            snapshots: VecDeque::new(),
+            render_planes: Vec::new(),
+            input_seq: 0,
+            last_input: Input::default(),
+            pending_inputs: VecDeque::new(),
         }
     }
 
@@ -34,39 +75,133 @@ impl Game {
         if is_key_down(KeyCode::Escape) {
             self.quit = true;
         }
-        const ROT_SPEED: f32 = 0.015;
 
-        if is_key_down(KeyCode::Right) {
-            self.player_state.rotation += ROT_SPEED;
+        self.input_seq += 1;
+        let input = Input {
+            left: is_key_down(KeyCode::Left),
+            right: is_key_down(KeyCode::Right),
+            thrust: true,
+            seq: self.input_seq,
+        };
+        self.last_input = input;
+
+        // Predict locally: apply the input now and remember it until the server
+        // acknowledges it.
+        self.pending_inputs.push_back(input);
+        simulate(&mut self.player_state, &input, 1.0);
+
+        self.interpolate_remotes();
+    }
+
+    // Start from the authoritative state and replay every input the server has
+    // not processed yet to catch back up to the present frame, then snap the
+    // local plane to the result or ease toward it (see `CORRECTION_THRESHOLD`).
+    fn reconcile(&mut self, auth: &RemoteState) {
+        while let Some(front) = self.pending_inputs.front() {
+            if front.seq <= auth.last_processed_input {
+                self.pending_inputs.pop_front();
+            } else {
+                break;
+            }
         }
-        if is_key_down(KeyCode::Left) {
-            self.player_state.rotation -= ROT_SPEED;
+
+        let mut corrected = auth.clone();
+        for input in &self.pending_inputs {
+            simulate(&mut corrected, input, 1.0);
         }
 
-        const SPEED: f32 = 0.6;
+        if self.player_state.position.distance(corrected.position) > CORRECTION_THRESHOLD {
+            self.player_state.position = corrected.position;
+            self.player_state.rotation = corrected.rotation;
+        } else {
+            self.player_state.position = self
+                .player_state
+                .position
+                .lerp(corrected.position, CORRECTION_SMOOTHING);
+            self.player_state.rotation =
+                lerp_angle(self.player_state.rotation, corrected.rotation, CORRECTION_SMOOTHING);
+        }
+        self.player_state.last_processed_input = auth.last_processed_input;
+    }
 
-        self.player_state.position += vec2_from_angle(self.player_state.rotation) * SPEED;
+    // Rebuild `render_planes` for the current frame by sampling the snapshot
+    // buffer at `now - INTERPOLATION_DELAY` and lerping between the two
+    // snapshots that bracket that render time.
+    //
+    // NOTE: we interpolate along each snapshot's local `received_at` arrival
+    // time rather than a server-stamped send time. The book's design threads a
+    // server `time_ms` through `Update` and interpolates against that; we drop
+    // it deliberately. A server clock only helps if we estimate the clock
+    // offset, and without that estimation a raw `time_ms` is no more accurate
+    // than arrival time while adding a field that can skew playback if the
+    // clocks drift. Arrival-time spacing keeps remote motion smooth on the one
+    // clock we can trust — our own.
+    fn interpolate_remotes(&mut self) {
+        self.render_planes.clear();
 
-        for state in &mut self.remote_states {
-            state.position += vec2_from_angle(state.rotation) * SPEED;
-        }
+        let render_time = get_time() - INTERPOLATION_DELAY;
 
-        if self.player_state.position.x > screen_width() {
-            self.player_state.position.x = -PLANE_WIDTH;
-        } else if self.player_state.position.x < -PLANE_WIDTH {
-            self.player_state.position.x = screen_width();
+        // The two snapshots bracketing the render time.
+        let mut older: Option<&Snapshot> = None;
+        let mut newer: Option<&Snapshot> = None;
+        for snap in &self.snapshots {
+            if snap.received_at <= render_time {
+                older = Some(snap);
+            } else {
+                newer = Some(snap);
+                break;
+            }
         }
 
-        if self.player_state.position.y > screen_height() {
-            self.player_state.position.y = -PLANE_HEIGHT;
-        } else if self.player_state.position.y < -PLANE_HEIGHT {
-            self.player_state.position.y = screen_height();
+        match (older, newer) {
+            (Some(a), Some(b)) => {
+                let span = (b.received_at - a.received_at) as f32;
+                let t = if span > 0. {
+                    ((render_time - a.received_at) as f32 / span).clamp(0., 1.)
+                } else {
+                    1.
+                };
+
+                for new in &b.states {
+                    if new.id == self.player_state.id {
+                        continue;
+                    }
+                    match a.states.iter().find(|s| s.id == new.id) {
+                        Some(old) => {
+                            let mut state = new.clone();
+                            state.position = old.position.lerp(new.position, t);
+                            state.rotation = lerp_angle(old.rotation, new.rotation, t);
+                            self.render_planes.push(RenderPlane { state, alpha: 1. });
+                        }
+                        // Appeared only in the newer snapshot: fade in over the
+                        // interpolation span instead of popping into existence.
+                        None => self.render_planes.push(RenderPlane {
+                            state: new.clone(),
+                            alpha: t,
+                        }),
+                    }
+                }
+            }
+            // Not enough history yet: show the freshest snapshot we have.
+            _ => {
+                if let Some(latest) = self.snapshots.back() {
+                    for state in &latest.states {
+                        if state.id == self.player_state.id {
+                            continue;
+                        }
+                        self.render_planes.push(RenderPlane {
+                            state: state.clone(),
+                            alpha: 1.,
+                        });
+                    }
+                }
+            }
         }
     }
 
-    pub fn draw_plane(&self, state: &RemoteState) {
+    pub fn draw_plane(&self, state: &RemoteState, alpha: f32) {
         let cols = (self.texture.width() / PLANE_WIDTH).floor() as usize;
-        let index = state.id % 10;
+        let index = skin_index(state);
         let tx_x = index % cols;
         let tx_y = index / cols;
 
@@ -74,7 +209,7 @@ impl Game {
             self.texture,
             state.position.x,
             state.position.y,
-            WHITE,
+            Color::new(1., 1., 1., alpha),
             DrawTextureParams {
                 source: Some(Rect::new(
                     tx_x as f32 * PLANE_WIDTH,
@@ -86,6 +221,17 @@ impl Game {
                 ..Default::default()
             },
         );
+
+        // Label the plane with a short prefix of its authenticated identity.
+        if let Some(identity) = &state.identity {
+            draw_text(
+                &identity[..identity.len().min(8)],
+                state.position.x,
+                state.position.y - 4.,
+                16.,
+                Color::new(0., 0., 0., alpha),
+            );
+        }
     }
 
     pub fn draw(&self) {
@@ -93,31 +239,99 @@ impl Game {
 
         draw_box(Vec2::new(400f32, 200f32), Vec2::new(50f32, 20f32));
 
-        self.draw_plane(&self.player_state);
+        self.draw_plane(&self.player_state, 1.);
 
-        for state in &self.remote_states {
-            self.draw_plane(state);
+        for plane in &self.render_planes {
+            self.draw_plane(&plane.state, plane.alpha);
         }
     }
 
-    pub fn handle_message(&mut self, msg: ServerMessage) {
+    // Apply a server message and optionally produce a reply to send back (e.g.
+    // a keep-alive `Pong`).
+    pub fn handle_message(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
         match msg {
-            ServerMessage::Welcome(id) => {
+            ServerMessage::Welcome { id, .. } => {
                 self.player_state.id = id;
             }
+            ServerMessage::Disconnect(reason) => {
+                log::warn!("disconnected by server: {}", reason);
+                self.quit = true;
+            }
             ServerMessage::GoodBye(id) => {
-                self.remote_states.retain(|s| s.id != id);
+                for snap in &mut self.snapshots {
+                    snap.states.retain(|s| s.id != id);
+                }
             }
-            ServerMessage::Update(remote_states) => {
-                self.remote_states = remote_states;
+            ServerMessage::Ping(nonce) => {
+                return Some(ClientMessage::Pong(nonce));
+            }
+            ServerMessage::Update { states, tick } => {
+                self.push_snapshot(states, tick);
+            }
+            ServerMessage::Delta {
+                base_tick,
+                tick,
+                changed,
+                removed,
+            } => {
+                // Deltas are relative to the snapshot we last buffered; if that
+                // base is gone we just wait for the next full update.
+                if let Some(base) = self.snapshots.iter().find(|s| s.tick == base_tick) {
+                    let mut states = base.states.clone();
+                    states.retain(|s| !removed.contains(&s.id));
+                    for state in changed {
+                        match states.iter_mut().find(|s| s.id == state.id) {
+                            Some(existing) => *existing = state,
+                            None => states.push(state),
+                        }
+                    }
+                    self.push_snapshot(states, tick);
+                }
             }
         }
+
+        None
+    }
+
+    // Reconcile the local plane against its authoritative state (if present) and
+    // append the new world snapshot to the interpolation buffer.
+    fn push_snapshot(&mut self, states: Vec<RemoteState>, tick: u32) {
+        if let Some(auth) = states.iter().find(|s| s.id == self.player_state.id) {
+            let auth = auth.clone();
+            self.reconcile(&auth);
+        }
+        self.snapshots.push_back(Snapshot {
+            received_at: get_time(),
+            tick,
+            states,
+        });
+        while self.snapshots.len() > SNAPSHOT_BUFFER {
+            self.snapshots.pop_front();
+        }
     }
 }
 
-pub fn vec2_from_angle(angle: f32) -> Vec2 {
-    let angle = angle - std::f32::consts::FRAC_PI_2;
-    Vec2::new(angle.cos(), angle.sin())
+// Pick a plane skin from the player's stable identity so it survives
+// reconnects, falling back to the connection id before a handshake completes.
+fn skin_index(state: &RemoteState) -> usize {
+    match &state.identity {
+        Some(identity) => identity
+            .bytes()
+            .fold(0usize, |acc, b| acc.wrapping_mul(31).wrapping_add(b as usize))
+            % 10,
+        None => state.id % 10,
+    }
+}
+
+// Interpolate between two angles along the shortest arc.
+fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    let mut delta = (b - a) % (2. * std::f32::consts::PI);
+    if delta > std::f32::consts::PI {
+        delta -= 2. * std::f32::consts::PI;
+    } else if delta < -std::f32::consts::PI {
+        delta += 2. * std::f32::consts::PI;
+    }
+    a + delta * t
 }
 
 fn draw_box(pos: Vec2, size: Vec2) {
@@ -127,8 +341,25 @@ fn draw_box(pos: Vec2, size: Vec2) {
     draw_rectangle(upper_left.x, upper_left.y, dimension.x, dimension.y, BLACK);
 }
 
-pub fn client_send(msg: &ClientMessage, connection: &mut Connection) {
-    let bytes = serde_json::to_vec(msg).expect("serialization failed");
+// Decode a hex-encoded 32-byte ed25519 identity (the form `identity_hex`
+// prints), returning `None` if it isn't well-formed.
+fn decode_identity(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() != 64 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|s| u8::from_str_radix(s, 16).ok()))
+        .collect()
+}
+
+pub fn client_send(msg: &ClientMessage, connection: &mut Connection, session: Option<&Session>) {
+    let bytes = bincode::serialize(msg).expect("serialization failed");
+    let bytes = match session {
+        Some(session) => session.encrypt(&bytes),
+        None => bytes,
+    };
     connection.send(bytes);
 }
 
@@ -139,20 +370,101 @@ async fn main() {
     let mut connection = Connection::new();
     connection.connect("ws://localhost:3030/game");
 
+    // Prove our identity and announce an ephemeral key for the session.
+    let signing = SigningKey::generate(&mut rand::rngs::OsRng);
+    let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let eph_pubkey = PublicKey::from(&secret);
+
+    // Our own stable identity, so the local plane uses the same skin as the
+    // server will advertise to everyone else.
+    game.player_state.identity = Some(crypto::identity_hex(&signing.verifying_key().to_bytes()));
+
+    let mut message = HANDSHAKE_CONTEXT.to_vec();
+    message.extend_from_slice(eph_pubkey.as_bytes());
+    let signature = signing.sign(&message);
+
+    let hello = ClientMessage::Hello {
+        identity: signing.verifying_key().to_bytes().to_vec(),
+        eph_pubkey: eph_pubkey.as_bytes().to_vec(),
+        signature: signature.to_bytes().to_vec(),
+    };
+    client_send(&hello, &mut connection, None);
+
+    // Optionally pin the server's ed25519 identity (hex) so a man in the middle
+    // with a valid self-signed key still can't impersonate the real server.
+    let pinned_identity = std::env::var("SERVER_IDENTITY")
+        .ok()
+        .and_then(|hex| decode_identity(&hex));
+
+    let mut session: Option<Session> = None;
+
     loop {
-        let state = ClientMessage::State(State {
-            pos: game.player_state.position,
-            r: game.player_state.rotation,
-        });
-        client_send(&state, &mut connection);
+        game.update();
+
+        // Only start sending gameplay once the session is established.
+        if session.is_some() {
+            let input = ClientMessage::Input(game.last_input);
+            client_send(&input, &mut connection, session.as_ref());
+        }
 
         if let Some(msg) = connection.poll() {
-            let msg: ServerMessage =
-                serde_json::from_slice(msg.as_slice()).expect("deserialization failed");
-            game.handle_message(msg);
+            // Welcome arrives in the clear; everything after it is encrypted.
+            let bytes = match &session {
+                Some(session) => session.decrypt(msg.as_slice()),
+                None => Some(msg),
+            };
+
+            if let Some(bytes) = bytes {
+                let msg: ServerMessage =
+                    bincode::deserialize(bytes.as_slice()).expect("deserialization failed");
+
+                if let ServerMessage::Welcome {
+                    server_key,
+                    server_identity,
+                    signature,
+                    ..
+                } = &msg
+                {
+                    // Authenticate the server before trusting its key: the
+                    // signature must cover our ephemeral key and the x25519 key
+                    // we're about to derive with, which rules out a man in the
+                    // middle substituting its own key.
+                    if !crypto::verify_server_bind(
+                        server_identity,
+                        eph_pubkey.as_bytes(),
+                        server_key,
+                        signature,
+                    ) {
+                        log::error!("server authentication failed; aborting");
+                        return;
+                    }
+
+                    match &pinned_identity {
+                        Some(pinned) if pinned == server_identity => {}
+                        Some(_) => {
+                            log::error!("server identity does not match SERVER_IDENTITY; aborting");
+                            return;
+                        }
+                        // Trust on first use: no pin configured, so accept the
+                        // identity but surface it so it can be pinned later.
+                        None => log::warn!(
+                            "connected to unpinned server identity: {}",
+                            crypto::identity_hex(server_identity)
+                        ),
+                    }
+
+                    if let Ok(key) = <[u8; 32]>::try_from(server_key.as_slice()) {
+                        let shared = secret.diffie_hellman(&PublicKey::from(key)).to_bytes();
+                        session = Some(Session::new(shared, crypto::DIRECTION_CLIENT));
+                    }
+                }
+
+                if let Some(reply) = game.handle_message(msg) {
+                    client_send(&reply, &mut connection, session.as_ref());
+                }
+            }
         }
 
-        game.update();
         game.draw();
         if game.quit {
             return;
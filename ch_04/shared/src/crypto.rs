@@ -0,0 +1,143 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Signed by the client over its ephemeral key so the server knows the
+// handshake is bound to this identity and not replayed from another session.
+pub const HANDSHAKE_CONTEXT: &[u8] = b"warp-ed25519-handshake-v1";
+
+// Signed by the server over the client's ephemeral key and its own x25519
+// public key. Binding the client's key in means the signature can't be replayed
+// from another session, and verifying it proves the `Welcome` came from the
+// holder of the server's long-term key rather than a man in the middle.
+pub const SERVER_CONTEXT: &[u8] = b"warp-server-bind-v1";
+
+// Direction tags keep the two halves of a session in separate nonce spaces
+// even though they share one key.
+pub const DIRECTION_SERVER: u8 = 1;
+pub const DIRECTION_CLIENT: u8 = 2;
+
+// Verify that `signature` over `HANDSHAKE_CONTEXT ++ eph_pubkey` was produced by
+// the ed25519 key `identity`.
+pub fn verify_hello(identity: &[u8], eph_pubkey: &[u8], signature: &[u8]) -> bool {
+    let key_bytes: [u8; 32] = match identity.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let sig_bytes: [u8; 64] = match signature.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let verifying_key = match VerifyingKey::from_bytes(&key_bytes) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = Signature::from_bytes(&sig_bytes);
+
+    let mut message = HANDSHAKE_CONTEXT.to_vec();
+    message.extend_from_slice(eph_pubkey);
+
+    verifying_key.verify(&message, &signature).is_ok()
+}
+
+// Sign `SERVER_CONTEXT ++ client_eph_pubkey ++ server_key` with the server's
+// long-term ed25519 key, binding the `Welcome` to this exchange.
+pub fn sign_server_bind(
+    signing_key: &SigningKey,
+    client_eph_pubkey: &[u8],
+    server_key: &[u8],
+) -> Vec<u8> {
+    let mut message = SERVER_CONTEXT.to_vec();
+    message.extend_from_slice(client_eph_pubkey);
+    message.extend_from_slice(server_key);
+
+    signing_key.sign(&message).to_bytes().to_vec()
+}
+
+// Verify the server binding produced by `sign_server_bind`. `identity` is the
+// server's ed25519 public key; the client supplies the same `client_eph_pubkey`
+// and `server_key` it saw in the handshake.
+pub fn verify_server_bind(
+    identity: &[u8],
+    client_eph_pubkey: &[u8],
+    server_key: &[u8],
+    signature: &[u8],
+) -> bool {
+    let key_bytes: [u8; 32] = match identity.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let sig_bytes: [u8; 64] = match signature.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let verifying_key = match VerifyingKey::from_bytes(&key_bytes) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = Signature::from_bytes(&sig_bytes);
+
+    let mut message = SERVER_CONTEXT.to_vec();
+    message.extend_from_slice(client_eph_pubkey);
+    message.extend_from_slice(server_key);
+
+    verifying_key.verify(&message, &signature).is_ok()
+}
+
+// A short, human-readable label for a player's stable identity.
+pub fn identity_hex(identity: &[u8]) -> String {
+    identity.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// An authenticated, encrypted channel keyed by the handshake's shared secret.
+// One `Session` encrypts this side's outgoing frames (tagging the nonce with
+// our `direction`) and decrypts the peer's frames using the nonce they send.
+pub struct Session {
+    cipher: ChaCha20Poly1305,
+    direction: u8,
+    counter: AtomicU64,
+}
+
+impl Session {
+    pub fn new(shared_secret: [u8; 32], direction: u8) -> Self {
+        let key = Key::from_slice(&shared_secret);
+
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            direction,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut nonce = [0u8; 12];
+        nonce[0] = self.direction;
+        nonce[4..12].copy_from_slice(&counter.to_le_bytes());
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encryption failed");
+
+        let mut frame = nonce.to_vec();
+        frame.extend_from_slice(&ciphertext);
+        frame
+    }
+
+    pub fn decrypt(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 12 {
+            return None;
+        }
+
+        let (nonce, ciphertext) = frame.split_at(12);
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
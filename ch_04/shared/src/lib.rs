@@ -1,27 +1,115 @@
 use glam::Vec2;
 use serde::{Deserialize, Serialize};
 
+pub mod crypto;
+
+pub const ROT_SPEED: f32 = 0.015;
+pub const SPEED: f32 = 0.6;
+
+pub const PLANE_WIDTH: f32 = 32.;
+pub const PLANE_HEIGHT: f32 = 32.;
+
+pub const WORLD_WIDTH: f32 = 800.;
+pub const WORLD_HEIGHT: f32 = 600.;
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct RemoteState {
     pub id: usize,
     pub position: Vec2,
     pub rotation: f32,
+    pub last_processed_input: u32,
+    // The authenticated identity behind this plane, if the session completed a
+    // handshake. Lets the client label planes and survives reconnects.
+    pub identity: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub struct Input {
+    pub left: bool,
+    pub right: bool,
+    pub thrust: bool,
+    pub seq: u32,
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum ServerMessage {
-    Welcome(usize),
+    // Sent in the clear right after a successful handshake; `server_key` is the
+    // server's x25519 public key the client needs to derive the session key.
+    // `server_identity` is the server's long-term ed25519 key and `signature`
+    // binds it to this exchange so the client can reject a man in the middle.
+    Welcome {
+        id: usize,
+        server_key: Vec<u8>,
+        server_identity: Vec<u8>,
+        signature: Vec<u8>,
+    },
     GoodBye(usize),
-    Update(Vec<RemoteState>),
+    Update {
+        states: Vec<RemoteState>,
+        tick: u32,
+    },
+    // An incremental update carrying only the planes whose quantized state
+    // changed since `base_tick`, plus the ids that disappeared. Applied on top
+    // of the client's buffered snapshot at `base_tick`.
+    Delta {
+        base_tick: u32,
+        tick: u32,
+        changed: Vec<RemoteState>,
+        removed: Vec<usize>,
+    },
+    Ping(u64),
+    // Reject a connection with a human-readable reason instead of closing the
+    // socket silently.
+    Disconnect(String),
 }
 
 #[derive(Deserialize, Serialize, Clone)]
-pub struct State {
-    pub pos: Vec2,
-    pub r: f32,
+pub enum ClientMessage {
+    // First frame of an authenticated session: the client's ed25519 identity,
+    // its ephemeral x25519 public key, and a signature binding the two.
+    Hello {
+        identity: Vec<u8>,
+        eph_pubkey: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    Input(Input),
+    Pong(u64),
 }
 
-#[derive(Deserialize, Serialize)]
-pub enum ClientMessage {
-    State(State),
+pub fn vec2_from_angle(angle: f32) -> Vec2 {
+    let angle = angle - std::f32::consts::FRAC_PI_2;
+    Vec2::new(angle.cos(), angle.sin())
+}
+
+/// Advance a plane by a single `dt` step of the authoritative simulation.
+///
+/// `dt` is expressed in ticks, so `dt == 1.0` reproduces the original
+/// per-frame integration. Keeping the `ROT_SPEED`/`SPEED` math and the
+/// screen wrap in one place lets the server and the client's prediction run
+/// the exact same motion.
+pub fn simulate(state: &mut RemoteState, input: &Input, dt: f32) {
+    if input.right {
+        state.rotation += ROT_SPEED * dt;
+    }
+    if input.left {
+        state.rotation -= ROT_SPEED * dt;
+    }
+
+    if input.thrust {
+        state.position += vec2_from_angle(state.rotation) * SPEED * dt;
+    }
+
+    if state.position.x > WORLD_WIDTH {
+        state.position.x = -PLANE_WIDTH;
+    } else if state.position.x < -PLANE_WIDTH {
+        state.position.x = WORLD_WIDTH;
+    }
+
+    if state.position.y > WORLD_HEIGHT {
+        state.position.y = -PLANE_HEIGHT;
+    } else if state.position.y < -PLANE_HEIGHT {
+        state.position.y = WORLD_HEIGHT;
+    }
+
+    state.last_processed_input = input.seq;
 }